@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+
+use clap::Parser;
+
+/// Runtime configuration for mpd-stream-proxy.
+///
+/// Every field can be set via CLI flag or the matching environment
+/// variable, with the flag taking precedence.
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about)]
+pub struct Config {
+    /// Address to bind the HTTP server to.
+    #[arg(long, env = "MPDSP_ADDR", default_value = "127.0.0.1:4000")]
+    pub addr: SocketAddr,
+
+    /// Path or name of the `yt-dlp` executable to spawn.
+    #[arg(long, env = "MPDSP_YTDL", default_value = "yt-dlp")]
+    pub ytdl_path: String,
+
+    /// Format selector passed to `yt-dlp -f`.
+    #[arg(long, env = "MPDSP_FORMAT", default_value = "bestaudio")]
+    pub format: String,
+
+    /// Time-to-live, in seconds, for cached stream info.
+    #[arg(long, env = "MPDSP_CACHE_TTL", default_value_t = 600)]
+    pub cache_ttl: u64,
+
+    /// Initial capacity of the stream info cache.
+    #[arg(long, env = "MPDSP_CACHE_CAPACITY", default_value_t = 10)]
+    pub cache_capacity: usize,
+
+    /// Maximum number of attempts for yt-dlp invocations and upstream
+    /// requests before giving up. Must be at least 1.
+    #[arg(
+        long,
+        env = "MPDSP_RETRY_ATTEMPTS",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub retry_attempts: u32,
+
+    /// Maximum backoff delay, in seconds, between retries.
+    #[arg(long, env = "MPDSP_RETRY_MAX_DELAY", default_value_t = 30)]
+    pub retry_max_delay: u64,
+}
@@ -1,18 +1,36 @@
 use anyhow::{anyhow, Error, Result};
-use hyper::{body::HttpBody, Body, Request, Response, Server, StatusCode, Uri};
+use clap::Parser;
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderMap, HeaderValue},
+    Body, Request, Response, Server, StatusCode, Uri,
+};
 
 use log::{debug, error, info, trace, warn};
 use moka::future::Cache;
+use rand::Rng;
 use serde_json::Value;
-use std::{cmp::Ordering, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    cmp::Ordering,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-const YTDL: &str = "yt-dlp";
-const USAGE: &str = "Usage: GET /<URL>/[cover.*]";
+mod config;
+mod metrics;
+
+use config::Config;
+
+const USAGE: &str = "Usage: GET /<URL>/[cover.*|playlist.m3u[8]]";
 
 #[derive(Clone)]
 struct Context {
     client: hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
     ytdl_cache: Cache<String, Arc<Value>>,
+    config: Arc<Config>,
 }
 
 #[tokio::main]
@@ -21,14 +39,19 @@ async fn main() {
 
     simple_logger::init_with_env().unwrap();
 
+    let config = Config::parse();
+
     let cx = Context {
         client: hyper::Client::builder().build::<_, Body>(hyper_tls::HttpsConnector::new()),
         ytdl_cache: Cache::builder()
-            .initial_capacity(10)
-            .time_to_live(Duration::from_secs(600))
+            .initial_capacity(config.cache_capacity)
+            .time_to_live(Duration::from_secs(config.cache_ttl))
             .build(),
+        config: Arc::new(config),
     };
 
+    let addr = cx.config.addr;
+
     // A `MakeService` that produces a `Service` to handle each connection.
     let make_service = make_service_fn(move |_socket| {
         let cx = cx.clone();
@@ -49,7 +72,6 @@ async fn main() {
         async move { Ok::<_, Error>(service) }
     });
 
-    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 4000));
     let server = Server::bind(&addr).serve(make_service);
 
     if let Err(e) = server.await {
@@ -61,18 +83,40 @@ async fn handle_request(mut request: Request<Body>, cx: Context) -> Result<Respo
     let Context {
         client,
         ytdl_cache: cache,
+        config,
     } = cx;
 
-    let (input, cover_ext, is_asking_cover) =
-        extract_input(request.uri().path_and_query().unwrap().as_str())?;
+    if request.uri().path() == "/metrics" {
+        return metrics_response();
+    }
+
+    let (input, kind) = extract_input(request.uri().path_and_query().unwrap().as_str())?;
     info!("input: {input}");
 
+    if kind == Kind::Playlist {
+        metrics::REQUEST_KIND.with_label_values(&["playlist"]).inc();
+        info!("asking for playlist.m3u");
+
+        let infos = ask_stream_infos_with_retry(&input, &config).await?;
+        for info in &infos {
+            let key = key_from_info(info)?.to_string();
+            cache.insert(key, Arc::new(info.clone())).await;
+        }
+
+        let body = playlist_m3u(&infos, &proxy_base_url(&request)?)?;
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "audio/x-mpegurl")
+            .body(Body::from(body))?);
+    }
+
     let info = if let Some(info) = cache.get(&input) {
+        metrics::CACHE_HITS.inc();
         info
     } else {
+        metrics::CACHE_MISSES.inc();
         info!("updating cache");
 
-        for info in ask_stream_infos(&input).await? {
+        for info in ask_stream_infos_with_retry(&input, &config).await? {
             let key = key_from_info(&info)?.to_string();
             cache.insert(key, Arc::new(info)).await;
         }
@@ -81,49 +125,310 @@ async fn handle_request(mut request: Request<Body>, cx: Context) -> Result<Respo
             .expect(r#""input" to be equal to one "original_url""#)
     };
 
-    let proxied_url = if is_asking_cover {
-        info!("asking for cover.{}", &cover_ext);
-        cover_url_from_info(&info, &cover_ext)?
-    } else {
-        stream_url_from_info(&info)?
+    let proxied_url = match &kind {
+        Kind::Cover(cover_ext) => {
+            metrics::REQUEST_KIND.with_label_values(&["cover"]).inc();
+            info!("asking for cover.{cover_ext}");
+            cover_url_from_info(&info, cover_ext)?
+        }
+        Kind::Stream => {
+            metrics::REQUEST_KIND.with_label_values(&["stream"]).inc();
+            stream_url_from_info(&info)?
+        }
+        Kind::Playlist => unreachable!("handled above"),
     };
 
     debug!("proxied_url: {}", proxied_url);
 
+    let etag = etag_of(&input, proxied_url);
+    let last_modified = last_modified_from_info(&info);
+
+    if is_not_modified(request.headers(), &etag, last_modified) {
+        debug!("resource not modified, replying 304");
+        return Ok(not_modified_response(&etag, last_modified));
+    }
+
     *request.uri_mut() = Uri::from_str(proxied_url)?;
     request.headers_mut().remove("host");
 
     trace!("request: {request:#?}");
-    let response = client.request(request).await?;
+    let mut response = request_with_retry(
+        &client,
+        request.method(),
+        request.uri(),
+        request.headers(),
+        &config,
+    )
+    .await?;
     debug!("response: {response:#?}");
 
+    if let Kind::Cover(cover_ext) = &kind {
+        // The upstream thumbnail host frequently sends a wrong or missing
+        // Content-Type; trust the requested extension instead when we can
+        // guess a MIME type for it.
+        if let Some(mime) = mime_guess::from_ext(cover_ext).first() {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_str(mime.as_ref())?);
+        }
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag)?);
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(&format!("public, max-age={}", config.cache_ttl))?,
+    );
+    if let Some(last_modified) = last_modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&httpdate::fmt_http_date(last_modified))?,
+        );
+    }
+
     Ok(response)
 }
 
-fn extract_input(path_and_query: &str) -> Result<(String, String, bool)> {
-    let (input, last) = path_and_query.rsplit_once('/').unwrap_or_default();
+/// Serves the Prometheus text exposition format for all registered metrics.
+fn metrics_response() -> Result<Response<Body>> {
+    let buffer = metrics::gather()?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(buffer))?)
+}
+
+/// Derives a stable, weak-free ETag from the original input URL and the
+/// resolved URL being proxied.
+fn etag_of(original_url: &str, url: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    original_url.hash(&mut hasher);
+    url.hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
 
-    let is_asking_cover = last.starts_with("cover.");
-    if !last.is_empty() && !is_asking_cover {
-        Err(anyhow!("No '/' or '/cover.*' after the URL."))?
+/// Reads yt-dlp's `timestamp`/`release_timestamp` field, when present, as a
+/// `Last-Modified` instant.
+fn last_modified_from_info(info: &Value) -> Option<SystemTime> {
+    let timestamp = info
+        .get("timestamp")
+        .or_else(|| info.get("release_timestamp"))?
+        .as_i64()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64))
+}
+
+/// Checks the incoming `If-None-Match` and `If-Modified-Since` headers
+/// against the resource's current `ETag`/`Last-Modified`.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|v| v == etag);
     }
 
-    let input = input.trim_start_matches('/');
-    if input.is_empty() {
-        Err(anyhow!("Empty URL. {USAGE}"))?
+    if let (Some(if_modified_since), Some(last_modified)) =
+        (headers.get(header::IF_MODIFIED_SINCE), last_modified)
+    {
+        if let Ok(since) = if_modified_since
+            .to_str()
+            .map_err(|_| ())
+            .and_then(|v| httpdate::parse_http_date(v).map_err(|_| ()))
+        {
+            return last_modified <= since;
+        }
     }
 
-    let cover_ext = if is_asking_cover {
-        let cover_ext = last.split_once('.').expect("cover to have a '.'").1;
-        if cover_ext.is_empty() {
-            Err(anyhow!("cover asked has no extension"))?
+    false
+}
+
+/// Initial delay before the first retry; doubled on each subsequent attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Calls `ask_stream_infos`, retrying on failure with exponential backoff
+/// and jitter, up to `config.retry_attempts` times.
+async fn ask_stream_infos_with_retry(input: &str, config: &Config) -> Result<Vec<Value>> {
+    let max_delay = Duration::from_secs(config.retry_max_delay);
+    let mut delay = INITIAL_RETRY_DELAY;
+
+    for attempt in 1..=config.retry_attempts {
+        match ask_stream_infos(input, config).await {
+            Ok(infos) => return Ok(infos),
+            Err(e) if attempt == config.retry_attempts => return Err(e),
+            Err(e) => {
+                warn!(
+                    "yt-dlp attempt {attempt}/{} failed: {e}, retrying in {delay:?}",
+                    config.retry_attempts
+                );
+                tokio::time::sleep(delay).await;
+                delay = next_delay(delay, max_delay);
+            }
         }
-        cover_ext.to_string()
-    } else {
-        String::new()
+    }
+
+    unreachable!("loop above always returns by the last attempt")
+}
+
+/// Issues the upstream request, retrying connection errors and
+/// 5xx/429 responses with exponential backoff and jitter, up to
+/// `config.retry_attempts` times. The request is rebuilt from its parts on
+/// every attempt since a `hyper::Body` cannot be replayed.
+async fn request_with_retry(
+    client: &hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    method: &hyper::Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    config: &Config,
+) -> Result<Response<Body>> {
+    let max_delay = Duration::from_secs(config.retry_max_delay);
+    let mut delay = INITIAL_RETRY_DELAY;
+
+    for attempt in 1..=config.retry_attempts {
+        let mut builder = Request::builder().method(method.clone()).uri(uri.clone());
+        *builder
+            .headers_mut()
+            .expect("builder has no error at this point") = headers.clone();
+        let request = builder.body(Body::empty())?;
+
+        match client.request(request).await {
+            Ok(response) => {
+                metrics::UPSTREAM_STATUS
+                    .with_label_values(&[response.status().as_str()])
+                    .inc();
+
+                if !is_retryable_status(response.status()) || attempt == config.retry_attempts {
+                    return Ok(response);
+                }
+
+                warn!(
+                    "upstream responded {} on attempt {attempt}/{}, retrying in {delay:?}",
+                    response.status(),
+                    config.retry_attempts
+                );
+            }
+            Err(e) if attempt == config.retry_attempts => Err(e)?,
+            Err(e) => warn!(
+                "upstream request failed on attempt {attempt}/{}: {e}, retrying in {delay:?}",
+                config.retry_attempts
+            ),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = next_delay(delay, max_delay);
+    }
+
+    unreachable!("loop above always returns by the last attempt")
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Doubles `delay`, caps it at `max`, then adds a small random jitter so
+/// that concurrent requests don't all retry in lockstep.
+fn next_delay(delay: Duration, max: Duration) -> Duration {
+    let doubled = delay.saturating_mul(2).min(max);
+    let jitter = rand::thread_rng().gen_range(0..=doubled.as_millis() as u64 / 10 + 1);
+    doubled + Duration::from_millis(jitter)
+}
+
+fn not_modified_response(etag: &str, last_modified: Option<SystemTime>) -> Response<Body> {
+    let mut builder = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag);
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(last_modified));
+    }
+    builder
+        .body(Body::empty())
+        .expect("304 response is always valid")
+}
+
+/// What the client is asking this proxy to resolve the target URL into.
+#[derive(Debug, PartialEq, Eq)]
+enum Kind {
+    /// Proxy the resolved stream itself.
+    Stream,
+    /// Proxy the track's cover art, in the given extension.
+    Cover(String),
+    /// Generate an M3U playlist of every track the URL resolves to.
+    Playlist,
+}
+
+fn extract_input(path_and_query: &str) -> Result<(String, Kind)> {
+    // Split off a trailing "/cover.<ext>" or "/playlist.m3u[8]" *before*
+    // percent-decoding, so that an encoded slash (`%2F`) inside the target
+    // URL or its query string can never be mistaken for this proxy's own
+    // path separator. Anything else trailing a slash is just part of the
+    // URL, not an error.
+    let (url, kind) = match path_and_query.rsplit_once('/') {
+        Some((url, last)) if last.starts_with("cover.") => {
+            let cover_ext = last.split_once('.').expect("cover to have a '.'").1;
+            if cover_ext.is_empty() {
+                Err(anyhow!("cover asked has no extension"))?
+            }
+            (url, Kind::Cover(cover_ext.to_string()))
+        }
+        Some((url, "playlist.m3u" | "playlist.m3u8")) => (url, Kind::Playlist),
+        _ => (path_and_query, Kind::Stream),
     };
 
-    Ok((input.to_string(), cover_ext, is_asking_cover))
+    let url = url.trim_start_matches('/');
+    let url = percent_encoding::percent_decode_str(url)
+        .decode_utf8()
+        .map_err(|e| anyhow!("URL is not valid UTF-8 once percent-decoded: {e}"))?;
+
+    if url.is_empty() {
+        Err(anyhow!("Empty URL. {USAGE}"))?
+    }
+
+    Ok((url.into_owned(), kind))
+}
+
+/// Builds `<scheme>://<host>` from the incoming request's `Host` header,
+/// used to point playlist entries back at this proxy. The scheme is taken
+/// from `X-Forwarded-Proto` when present (set by the reverse proxy this
+/// service is typically run behind), falling back to `http`.
+fn proxy_base_url(request: &Request<Body>) -> Result<String> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .ok_or(anyhow!("request has no \"Host\" header"))?
+        .to_str()?;
+    let scheme = request
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    Ok(format!("{scheme}://{host}"))
+}
+
+/// Renders an extended M3U listing every resolved track, each pointing back
+/// at this proxy so MPD fetches (and re-resolves) them lazily.
+fn playlist_m3u(infos: &[Value], base_url: &str) -> Result<String> {
+    use std::fmt::Write as _;
+
+    let mut m3u = String::from("#EXTM3U\n");
+
+    for info in infos {
+        let original_url = key_from_info(info)?;
+        let duration = info.get("duration").and_then(Value::as_f64).unwrap_or(0.0);
+        let title = info.get("title").and_then(Value::as_str).unwrap_or("Unknown");
+        let artist = info
+            .get("artist")
+            .and_then(Value::as_str)
+            .or_else(|| info.get("uploader").and_then(Value::as_str));
+
+        let display_name = match artist {
+            Some(artist) => format!("{artist} - {title}"),
+            None => title.to_string(),
+        };
+        let display_name: String = display_name.chars().filter(|c| !c.is_control()).collect();
+
+        let encoded_url =
+            percent_encoding::utf8_percent_encode(original_url, percent_encoding::NON_ALPHANUMERIC);
+
+        writeln!(m3u, "#EXTINF:{},{display_name}", duration as i64)?;
+        writeln!(m3u, "{base_url}/{encoded_url}")?;
+    }
+
+    Ok(m3u)
 }
 
 fn key_from_info(info: &Value) -> Result<&str> {
@@ -168,9 +473,12 @@ fn cover_url_from_info<'a>(info: &'a Value, cover_ext: &str) -> Result<&'a str>
         ))
 }
 
-async fn ask_stream_infos(input: &str) -> Result<Vec<Value>> {
-    let child = tokio::process::Command::new(YTDL)
-        .args(["-f", "bestaudio", "-j", input])
+async fn ask_stream_infos(input: &str, config: &Config) -> Result<Vec<Value>> {
+    metrics::YTDL_INVOCATIONS.inc();
+    let _timer = metrics::YTDL_DURATION.start_timer();
+
+    let child = tokio::process::Command::new(&config.ytdl_path)
+        .args(["-f", &config.format, "-j", input])
         .stdout(std::process::Stdio::piped())
         .spawn()?;
 
@@ -188,11 +496,83 @@ async fn ask_stream_infos(input: &str) -> Result<Vec<Value>> {
             }
 
             if infos.is_empty() {
-                Err(anyhow!("received no info from {YTDL}."))?
+                Err(anyhow!("received no info from {}.", config.ytdl_path))?
             }
 
+            metrics::YTDL_TRACKS.observe(infos.len() as f64);
             Ok(infos)
         }
         false => Err(anyhow!("child process failed to gather info."))?,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_input, Kind};
+
+    #[test]
+    fn plain_url_without_cover() {
+        let (url, kind) = extract_input("/https://youtu.be/dQw4w9WgXcQ").unwrap();
+        assert_eq!(url, "https://youtu.be/dQw4w9WgXcQ");
+        assert_eq!(kind, Kind::Stream);
+    }
+
+    #[test]
+    fn url_with_query_string_and_no_cover() {
+        let (url, kind) =
+            extract_input("/https://youtu.be/watch?v=dQw4w9WgXcQ&list=PL123").unwrap();
+        assert_eq!(url, "https://youtu.be/watch?v=dQw4w9WgXcQ&list=PL123");
+        assert_eq!(kind, Kind::Stream);
+    }
+
+    #[test]
+    fn url_with_query_string_and_cover() {
+        let (url, kind) =
+            extract_input("/https://youtu.be/watch?v=dQw4w9WgXcQ&list=PL123/cover.jpg").unwrap();
+        assert_eq!(url, "https://youtu.be/watch?v=dQw4w9WgXcQ&list=PL123");
+        assert_eq!(kind, Kind::Cover("jpg".to_string()));
+    }
+
+    #[test]
+    fn percent_encoded_url_is_decoded() {
+        let (url, kind) =
+            extract_input("/https%3A%2F%2Fyoutu.be%2Fwatch%3Fv%3DdQw4w9WgXcQ/cover.png").unwrap();
+        assert_eq!(url, "https://youtu.be/watch?v=dQw4w9WgXcQ");
+        assert_eq!(kind, Kind::Cover("png".to_string()));
+    }
+
+    #[test]
+    fn encoded_slash_before_cover_like_suffix_is_not_mistaken_for_real_cover() {
+        // The `%2F` here is part of the target URL's own path, encoded so a
+        // reverse proxy won't mangle it. It must not be treated as the
+        // "/cover.*" separator, even though the decoded text ends the same
+        // way a genuine cover request would.
+        let (url, kind) = extract_input("/https://example.com/track%2Fcover.jpg").unwrap();
+        assert_eq!(url, "https://example.com/track/cover.jpg");
+        assert_eq!(kind, Kind::Stream);
+    }
+
+    #[test]
+    fn playlist_suffix_is_recognized() {
+        let (url, kind) =
+            extract_input("/https://youtube.com/playlist?list=PL123/playlist.m3u").unwrap();
+        assert_eq!(url, "https://youtube.com/playlist?list=PL123");
+        assert_eq!(kind, Kind::Playlist);
+
+        let (url, kind) =
+            extract_input("/https://youtube.com/playlist?list=PL123/playlist.m3u8").unwrap();
+        assert_eq!(url, "https://youtube.com/playlist?list=PL123");
+        assert_eq!(kind, Kind::Playlist);
+    }
+
+    #[test]
+    fn empty_url_is_rejected() {
+        assert!(extract_input("/").is_err());
+        assert!(extract_input("/cover.jpg").is_err());
+    }
+
+    #[test]
+    fn cover_without_extension_is_rejected() {
+        assert!(extract_input("/https://youtu.be/dQw4w9WgXcQ/cover.").is_err());
+    }
+}
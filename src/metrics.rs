@@ -0,0 +1,89 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+
+/// Registry backing the `/metrics` endpoint.
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+pub static CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new("mpdsp_cache_hits_total", "ytdl_cache lookups that hit").unwrap())
+});
+
+pub static CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new("mpdsp_cache_misses_total", "ytdl_cache lookups that missed").unwrap(),
+    )
+});
+
+pub static YTDL_INVOCATIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "mpdsp_ytdl_invocations_total",
+            "number of yt-dlp child processes spawned",
+        )
+        .unwrap(),
+    )
+});
+
+pub static YTDL_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "mpdsp_ytdl_duration_seconds",
+            "wall-clock time spent waiting on a yt-dlp invocation",
+        ))
+        .unwrap(),
+    )
+});
+
+pub static YTDL_TRACKS: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "mpdsp_ytdl_tracks_resolved",
+                "number of tracks resolved per yt-dlp invocation",
+            )
+            .buckets(vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0]),
+        )
+        .unwrap(),
+    )
+});
+
+pub static UPSTREAM_STATUS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "mpdsp_upstream_responses_total",
+                "upstream responses, by status code",
+            ),
+            &["status"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static REQUEST_KIND: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("mpdsp_requests_total", "resolved requests, by kind"),
+            &["kind"],
+        )
+        .unwrap(),
+    )
+});
+
+fn register<T: Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registration to succeed");
+    metric
+}
+
+/// Renders all registered metrics in the Prometheus text exposition format.
+pub fn gather() -> prometheus::Result<Vec<u8>> {
+    let metric_families = REGISTRY.gather();
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metric_families, &mut buffer)?;
+    Ok(buffer)
+}